@@ -1,11 +1,15 @@
 use bevy::prelude::*;
 use bevy::{
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
+    input::mouse::MouseMotion,
     window::WindowResolution,
 };
 use bevy_pixels::prelude::*;
 use rand::{rngs::StdRng, Rng, SeedableRng};
-use ray_tracing::{vec3, Camera, Color, Hit, HitPoint, Material, Sphere, Vector};
+use ray_tracing::{
+    vec3, Aabb, Camera, Color, Cuboid, Hit, HitPoint, Material, MovingSphere, Sphere, Vector,
+};
+use std::io::Write;
 
 #[derive(Resource)]
 struct Random(StdRng);
@@ -13,26 +17,130 @@ struct Random(StdRng);
 #[derive(Resource)]
 struct World {
     camera: ray_tracing::FiniteApertureCamera,
-    objects: Vec<Object>,
+    bvh: BvhNode,
+    sky: bool,
 }
 
 struct Object {
-    sphere: Sphere,
+    shape: Box<dyn Hit>,
     material: Material,
 }
 
-impl World {
-    fn from_rng<R: Rng>(rng: &mut R, aspect_ratio: f64) -> World {
-        let camera = ray_tracing::CameraBuilder::new()
-            .look_from(vec3!(13.0, 2.0, 3.0))
-            .loot_at(vec3!(0.0))
+enum BvhContent {
+    Leaf(Vec<Object>),
+    Node(Box<BvhNode>, Box<BvhNode>),
+}
+
+struct BvhNode {
+    bbox: Aabb,
+    content: BvhContent,
+}
+
+impl BvhNode {
+    // Built with its own RNG stream (not the shared `Random` resource) so adding the BVH
+    // doesn't shift later per-frame sample draws away from the pre-BVH baseline.
+    fn build(objects: Vec<Object>) -> BvhNode {
+        BvhNode::build_with(&mut StdRng::seed_from_u64(0xB474), objects)
+    }
+
+    fn build_with<R: Rng>(rng: &mut R, mut objects: Vec<Object>) -> BvhNode {
+        if objects.len() <= 2 {
+            let bbox = objects
+                .iter()
+                .map(|o| o.shape.bounding_box())
+                .reduce(|a, b| a.surrounding(&b))
+                .expect("bvh leaf is never built from an empty slice");
+            return BvhNode {
+                bbox,
+                content: BvhContent::Leaf(objects),
+            };
+        }
+        let axis = rng.gen_range(0..3);
+        let centroid = |o: &Object| {
+            let bbox = o.shape.bounding_box();
+            (axis_component(bbox.min(), axis) + axis_component(bbox.max(), axis)) * 0.5
+        };
+        objects.sort_by(|a, b| centroid(a).partial_cmp(&centroid(b)).unwrap());
+        let right_half = objects.split_off(objects.len() / 2);
+        let left = BvhNode::build_with(rng, objects);
+        let right = BvhNode::build_with(rng, right_half);
+        let bbox = left.bbox.surrounding(&right.bbox);
+        BvhNode {
+            bbox,
+            content: BvhContent::Node(Box::new(left), Box::new(right)),
+        }
+    }
+
+    fn hit(&self, ray: &ray_tracing::Ray, t_max: f64) -> Option<(HitPoint, &Material)> {
+        if !self.bbox.hit(ray, 0.001, t_max) {
+            return None;
+        }
+        match &self.content {
+            BvhContent::Leaf(objects) => {
+                let mut t_max = t_max;
+                let mut hit = None;
+                for o in objects {
+                    if let Some(new_hit) = o.shape.hit(ray, t_max) {
+                        t_max = new_hit.t;
+                        hit = Some((new_hit, &o.material));
+                    }
+                }
+                hit
+            }
+            BvhContent::Node(left, right) => {
+                let left_hit = left.hit(ray, t_max);
+                let t_max = left_hit.as_ref().map_or(t_max, |(hit, _)| hit.t);
+                right.hit(ray, t_max).or(left_hit)
+            }
+        }
+    }
+}
+
+fn axis_component(v: Vector, axis: usize) -> f64 {
+    match axis {
+        0 => v.x(),
+        1 => v.y(),
+        _ => v.z(),
+    }
+}
+
+#[derive(Resource)]
+struct CameraState {
+    look_from: Vector,
+    look_at: Vector,
+    aperture: f64,
+    focus_dist: f64,
+}
+
+impl CameraState {
+    fn build(&self, aspect_ratio: f64) -> ray_tracing::FiniteApertureCamera {
+        ray_tracing::CameraBuilder::new()
+            .look_from(self.look_from)
+            .loot_at(self.look_at)
             .vertical_field_of_view(20.0)
             .aspect_ratio(aspect_ratio)
-            .blur(0.1);
+            .shutter(0.0, 1.0)
+            .focus_dist(self.focus_dist)
+            .blur(self.aperture)
+    }
+}
+
+fn cross(a: Vector, b: Vector) -> Vector {
+    vec3!(
+        a.y() * b.z() - a.z() * b.y(),
+        a.z() * b.x() - a.x() * b.z(),
+        a.x() * b.y() - a.y() * b.x()
+    )
+}
 
+impl World {
+    fn from_rng<R: Rng>(
+        rng: &mut R,
+        camera: ray_tracing::FiniteApertureCamera,
+    ) -> World {
         let mut objects = Vec::new();
         objects.push(Object {
-            sphere: Sphere::new(vec3!(0.0, -1000.0, 0.0), 1000.0),
+            shape: Box::new(Sphere::new(vec3!(0.0, -1000.0, 0.0), 1000.0)),
             material: Material::Lambertian {
                 color: Color::new(0.5, 0.5, 0.5),
             },
@@ -60,32 +168,61 @@ impl World {
                         index_of_refraction: 1.5,
                     }
                 };
-                objects.push(Object {
-                    sphere: Sphere::new(center, 0.2),
-                    material,
-                });
+                let shape: Box<dyn Hit> = if matches!(material, Material::Lambertian { .. })
+                    && rng.gen_bool(0.5)
+                {
+                    Box::new(MovingSphere {
+                        center0: center,
+                        center1: center + vec3!(0.0, rng.gen_range(0.0..0.5), 0.0),
+                        time0: 0.0,
+                        time1: 1.0,
+                        radius: 0.2,
+                    })
+                } else {
+                    Box::new(Sphere::new(center, 0.2))
+                };
+                objects.push(Object { shape, material });
             }
         }
         objects.push(Object {
-            sphere: Sphere::new(vec3!(0.0, 1.0, 0.0), 1.0),
+            shape: Box::new(Sphere::new(vec3!(0.0, 1.0, 0.0), 1.0)),
             material: Material::Dielectric {
                 index_of_refraction: 1.5,
             },
         });
         objects.push(Object {
-            sphere: Sphere::new(vec3!(-4.0, 1.0, 0.0), 1.0),
+            shape: Box::new(Sphere::new(vec3!(-4.0, 1.0, 0.0), 1.0)),
             material: Material::Lambertian {
                 color: Color::new(0.4, 0.2, 0.1),
             },
         });
         objects.push(Object {
-            sphere: Sphere::new(vec3!(4.0, 1.0, 0.0), 1.0),
+            shape: Box::new(Sphere::new(vec3!(4.0, 1.0, 0.0), 1.0)),
             material: Material::Metal {
                 color: Color::new(0.7, 0.6, 0.5),
                 fuzz: 0.0,
             },
         });
-        World { camera, objects }
+        objects.push(Object {
+            shape: Box::new(Cuboid::new(vec3!(-1.0, 0.0, -6.0), vec3!(1.0, 2.0, -4.0))),
+            material: Material::Metal {
+                color: Color::new(0.8, 0.8, 0.9),
+                fuzz: 0.0,
+            },
+        });
+        objects.push(Object {
+            shape: Box::new(Sphere::new(vec3!(0.0, 7.0, 0.0), 2.0)),
+            material: Material::DiffuseLight {
+                color: Color::new(1.0, 1.0, 1.0),
+                intensity: 4.0,
+            },
+        });
+        let bvh = BvhNode::build(objects);
+        World {
+            camera,
+            bvh,
+            sky: true,
+        }
     }
 
     fn get_ray<R: Rng>(
@@ -105,24 +242,17 @@ impl World {
         if depth == 0 {
             return ray_tracing::BLACK;
         }
-        let mut t_max = f64::INFINITY;
-        let mut hit: Option<(HitPoint, &Material)> = None;
-        for o in self.objects.iter() {
-            if let Some(new_hit) = o.sphere.hit(ray, t_max) {
-                if !matches!(hit, Some((ref now_hit, _)) if now_hit.t <= new_hit.t) {
-                    t_max = new_hit.t;
-                    hit = Some((new_hit, &o.material));
-                }
-            }
-        }
-        if let Some((hit, mate)) = hit {
+        if let Some((hit, mate)) = self.bvh.hit(ray, f64::INFINITY) {
+            let emitted = mate.emitted();
             if let Some((ray, attenuation)) = mate.scatter(rng, ray, &hit) {
-                attenuation * self.ray_color(rng, &ray, depth - 1)
+                emitted + attenuation * self.ray_color(rng, &ray, depth - 1)
             } else {
-                ray_tracing::BLACK
+                emitted
             }
-        } else {
+        } else if self.sky {
             ray.background()
+        } else {
+            ray_tracing::BLACK
         }
     }
 }
@@ -132,6 +262,7 @@ struct Pixels {
     width: u32,
     height: u32,
     pixels: Vec<(Color, u32)>,
+    samples: u64,
 }
 
 impl Pixels {
@@ -140,6 +271,7 @@ impl Pixels {
             width,
             height,
             pixels: vec![(ray_tracing::BLACK, 0); (width * height) as usize],
+            samples: 0,
         }
     }
 
@@ -147,6 +279,7 @@ impl Pixels {
         let i = (y * self.width + x) as usize;
         self.pixels[i].0 += color;
         self.pixels[i].1 += 1;
+        self.samples += 1;
     }
 
     fn iter(&self) -> impl Iterator<Item = Color> + '_ {
@@ -158,6 +291,44 @@ impl Pixels {
             }
         })
     }
+
+    fn reset(&mut self) {
+        for p in self.pixels.iter_mut() {
+            *p = (ray_tracing::BLACK, 0);
+        }
+        self.samples = 0;
+    }
+
+    fn write_ppm(&self, path: &std::path::Path) -> std::io::Result<()> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        write!(file, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for color in self.iter() {
+            file.write_all(&[
+                (color.x() * 255.0).clamp(0.0, 255.0) as u8,
+                (color.y() * 255.0).clamp(0.0, 255.0) as u8,
+                (color.z() * 255.0).clamp(0.0, 255.0) as u8,
+            ])?;
+        }
+        Ok(())
+    }
+
+    fn write_png(&self, path: &std::path::Path) -> Result<(), image::ImageError> {
+        let mut image = image::RgbImage::new(self.width, self.height);
+        for (i, color) in self.iter().enumerate() {
+            let x = i as u32 % self.width;
+            let y = i as u32 / self.width;
+            image.put_pixel(
+                x,
+                y,
+                image::Rgb([
+                    (color.x() * 255.0).clamp(0.0, 255.0) as u8,
+                    (color.y() * 255.0).clamp(0.0, 255.0) as u8,
+                    (color.z() * 255.0).clamp(0.0, 255.0) as u8,
+                ]),
+            );
+        }
+        image.save(path)
+    }
 }
 
 const WIDTH: u32 = 600;
@@ -166,10 +337,20 @@ const SCALE_FACTOR: f32 = 2.0;
 
 fn main() {
     let mut rng = StdRng::from_entropy();
-    let world = World::from_rng(&mut rng, WIDTH as f64 / HEIGHT as f64);
+    let look_from = vec3!(13.0, 2.0, 3.0);
+    let look_at = vec3!(0.0);
+    let camera_state = CameraState {
+        look_from,
+        look_at,
+        aperture: 0.1,
+        focus_dist: (look_at - look_from).norm(),
+    };
+    let camera = camera_state.build(WIDTH as f64 / HEIGHT as f64);
+    let world = World::from_rng(&mut rng, camera);
     App::new()
         .insert_resource(Random(rng))
         .insert_resource(world)
+        .insert_resource(camera_state)
         .insert_resource(Pixels::new(WIDTH, HEIGHT))
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
@@ -194,10 +375,130 @@ fn main() {
             }),
         })
         .add_systems(Update, bevy::window::close_on_esc)
+        .add_systems(Update, camera_control)
+        .add_systems(Update, export_frame)
         .add_systems(PostUpdate, draw.in_set(PixelsSet::Draw))
         .run();
 }
 
+fn export_frame(keys: Res<ButtonInput<KeyCode>>, pixels: Res<Pixels>) {
+    if !keys.just_pressed(KeyCode::F12) {
+        return;
+    }
+    let ppm_path = std::path::PathBuf::from(format!("render_{}rays.ppm", pixels.samples));
+    if let Err(err) = pixels.write_ppm(&ppm_path) {
+        error!("failed to write {}: {err}", ppm_path.display());
+    } else {
+        info!("wrote {}", ppm_path.display());
+    }
+    let png_path = std::path::PathBuf::from(format!("render_{}rays.png", pixels.samples));
+    if let Err(err) = pixels.write_png(&png_path) {
+        error!("failed to write {}: {err}", png_path.display());
+    } else {
+        info!("wrote {}", png_path.display());
+    }
+}
+
+fn camera_control(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut mouse_motion: EventReader<MouseMotion>,
+    time: Res<Time>,
+    mut state: ResMut<CameraState>,
+    mut world: ResMut<World>,
+    mut pixels: ResMut<Pixels>,
+) {
+    let mut moved = false;
+    let forward = {
+        let v = state.look_at - state.look_from;
+        let n = v.norm();
+        if n > 1e-6 {
+            v / n
+        } else {
+            vec3!(0.0, 0.0, -1.0)
+        }
+    };
+    let right = {
+        let v = cross(forward, vec3!(0.0, 1.0, 0.0));
+        let n = v.norm();
+        if n > 1e-6 {
+            v / n
+        } else {
+            vec3!(1.0, 0.0, 0.0)
+        }
+    };
+    let speed = 3.0 * time.delta_seconds() as f64;
+    let mut offset = vec3!(0.0);
+    if keys.pressed(KeyCode::KeyW) {
+        offset = offset + forward * speed;
+    }
+    if keys.pressed(KeyCode::KeyS) {
+        offset = offset - forward * speed;
+    }
+    if keys.pressed(KeyCode::KeyD) {
+        offset = offset + right * speed;
+    }
+    if keys.pressed(KeyCode::KeyA) {
+        offset = offset - right * speed;
+    }
+    if keys.pressed(KeyCode::Space) {
+        offset = offset + vec3!(0.0, speed, 0.0);
+    }
+    if keys.pressed(KeyCode::ShiftLeft) {
+        offset = offset - vec3!(0.0, speed, 0.0);
+    }
+    if offset.norm() > 0.0 {
+        state.look_from = state.look_from + offset;
+        state.look_at = state.look_at + offset;
+        moved = true;
+    }
+
+    const MAX_PITCH: f64 = 89.0 * std::f64::consts::PI / 180.0;
+    let mouse_sensitivity = 0.002;
+    for ev in mouse_motion.read() {
+        let yaw = -ev.delta.x as f64 * mouse_sensitivity;
+        let pitch_delta = -ev.delta.y as f64 * mouse_sensitivity;
+        let look_dir = state.look_at - state.look_from;
+        let radius = look_dir.norm();
+        let horizontal = vec3!(look_dir.x(), 0.0, look_dir.z());
+        let horizontal_norm = horizontal.norm();
+        if radius > 1e-6 && horizontal_norm > 1e-6 {
+            let current_pitch = (look_dir.y() / radius).clamp(-1.0, 1.0).asin();
+            let new_pitch = (current_pitch + pitch_delta).clamp(-MAX_PITCH, MAX_PITCH);
+            let horizontal = horizontal / horizontal_norm;
+            let horizontal =
+                horizontal * yaw.cos() + cross(vec3!(0.0, 1.0, 0.0), horizontal) * yaw.sin();
+            let new_look_dir =
+                horizontal * (new_pitch.cos() * radius) + vec3!(0.0, new_pitch.sin() * radius, 0.0);
+            state.look_at = state.look_from + new_look_dir;
+            moved = true;
+        }
+    }
+
+    if keys.just_pressed(KeyCode::BracketLeft) {
+        state.aperture = (state.aperture - 0.02).max(0.0);
+        moved = true;
+    }
+    if keys.just_pressed(KeyCode::BracketRight) {
+        state.aperture += 0.02;
+        moved = true;
+    }
+    if keys.just_pressed(KeyCode::Comma) {
+        state.focus_dist = (state.focus_dist - 0.5).max(0.1);
+        moved = true;
+    }
+    if keys.just_pressed(KeyCode::Period) {
+        state.focus_dist += 0.5;
+        moved = true;
+    }
+
+    if moved {
+        world.camera = state.build(pixels.width as f64 / pixels.height as f64);
+        pixels.reset();
+    }
+}
+
+const SAMPLES_PER_FRAME: u32 = 10000;
+
 fn draw(
     mut buffer: Query<&mut PixelsWrapper>,
     mut rng: ResMut<Random>,
@@ -206,12 +507,33 @@ fn draw(
 ) {
     let Ok(mut wrapper) = buffer.get_single_mut() else { return };
     assert_eq!(wrapper.pixels.frame().len(), pixels.pixels.len() * 4);
-    for _ in 0..10000 {
-        let (x, y, ray) = world.get_ray(&mut rng.0, pixels.width, pixels.height);
-        let color = world.ray_color(&mut rng.0, &ray, 50);
-        let y = pixels.height - 1 - y;
-        pixels.add_color(x, y, color);
+
+    let pool = bevy::tasks::ComputeTaskPool::get();
+    let worker_count = pool.thread_num().max(1);
+    let samples_per_worker = SAMPLES_PER_FRAME / worker_count as u32;
+    let width = pixels.width;
+    let height = pixels.height;
+    let world = &*world;
+    let batches: Vec<Vec<(u32, u32, Color)>> = pool.scope(|scope| {
+        for _ in 0..worker_count {
+            let mut worker_rng = StdRng::from_rng(&mut rng.0).expect("failed to split worker rng");
+            scope.spawn(async move {
+                let mut samples = Vec::with_capacity(samples_per_worker as usize);
+                for _ in 0..samples_per_worker {
+                    let (x, y, ray) = world.get_ray(&mut worker_rng, width, height);
+                    let color = world.ray_color(&mut worker_rng, &ray, 50);
+                    samples.push((x, height - 1 - y, color));
+                }
+                samples
+            });
+        }
+    });
+    for batch in batches {
+        for (x, y, color) in batch {
+            pixels.add_color(x, y, color);
+        }
     }
+
     let frame = wrapper.pixels.frame_mut();
     for (i, color) in pixels.iter().enumerate() {
         frame[i * 4 + 0] = (color.x() * 255.0).clamp(0.0, 255.0) as u8;